@@ -0,0 +1,463 @@
+// LNP/BP Rust Library
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! BOLT-8 related structures and functions covering Lightning network
+//! transport layer
+
+pub mod tcp;
+pub mod tor;
+#[cfg(feature="use-websocket")]
+pub mod ws;
+#[cfg(feature="use-obfs")]
+pub mod obfs;
+
+pub use tcp::TcpTransport;
+pub use tor::{TorTransport, TOR_PROXY_DEFAULT_PORT};
+#[cfg(feature="use-tor")]
+pub use tor::{TorHiddenService, TOR_CONTROL_DEFAULT_PORT};
+#[cfg(feature="use-websocket")]
+pub use ws::{WsTransport, WsStream};
+#[cfg(feature="use-obfs")]
+pub use obfs::{ObfsStream, ObfsTransport, PaddingRange};
+
+use std::io;
+use std::fmt;
+use std::str::FromStr;
+use std::net::SocketAddr;
+
+#[cfg(feature="use-tokio")]
+use tokio::net::{TcpStream, TcpListener};
+#[cfg(feature="use-tokio")]
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, AsyncReadExt};
+
+#[cfg(not(feature="use-tokio"))]
+use std::net::{TcpStream, TcpListener};
+#[cfg(not(feature="use-tokio"))]
+use std::io::{AsyncRead, AsyncWrite, AsyncWriteExt, AsyncReadExt};
+
+use lightning::secp256k1;
+
+// We re-export this under more proper name (it's not per-channel encryptor,
+// it is per-connection transport-level encryptor)
+use lightning::ln::peers::conduit::Conduit as Encryptor;
+use lightning::ln::peers::handshake::PeerHandshake;
+
+use crate::common::internet::InetSocketAddr;
+use super::LIGHTNING_P2P_DEFAULT_PORT;
+
+
+pub const MAX_TRANSPORT_FRAME_SIZE: usize = 65569;
+
+/// The scheme a [`NodeAddr`] was specified with, i.e. which [`Transport`]
+/// should be used to reach it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddrScheme {
+    /// Plain (or Tor-proxied, depending on `inet_addr`) TCP
+    Tcp,
+    /// WebSocket, `ws://host:port`
+    Ws,
+    /// WebSocket over TLS, `wss://host:port`
+    Wss,
+}
+
+impl Default for AddrScheme {
+    fn default() -> Self {
+        AddrScheme::Tcp
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct NodeAddr {
+    pub node_id: secp256k1::PublicKey,
+    pub inet_addr: InetSocketAddr,
+    pub scheme: AddrScheme,
+    /// Whether the BOLT-8 stream should be wrapped in the `obfs`-style
+    /// obfuscation layer (see [`obfs::ObfsStream`]) before the handshake
+    /// starts. Negotiated out of band: both peers need a matching
+    /// pre-shared secret, which isn't part of the address itself.
+    pub obfuscated: bool,
+}
+
+impl NodeAddr {
+    /// Picks a [`Transport`] based on `self.scheme` (falling back to a Tor
+    /// proxy dial for `.onion` `inet_addr`s under [`AddrScheme::Tcp`]),
+    /// optionally wraps it in the obfuscation layer when `self.obfuscated`
+    /// is set, and drives the outbound BOLT-8 handshake over the
+    /// resulting stream. `obfs_secret` is required and ignored
+    /// respectively depending on `self.obfuscated`. Without the `use-obfs`
+    /// feature `self.obfuscated` is not acted upon and the stream is used
+    /// unwrapped.
+    pub async fn connect(&self,
+                   private_key: &secp256k1::SecretKey,
+                   ephemeral_private_key: &secp256k1::SecretKey,
+                   proxy_addr: Option<SocketAddr>,
+                   #[cfg(feature="use-obfs")]
+                   obfs_secret: Option<[u8; 32]>
+    ) -> Result<Connection<Box<dyn AsyncDuplex>>, ConnectionError> {
+        let stream: Box<dyn AsyncDuplex> = match self.scheme {
+            AddrScheme::Tcp if self.inet_addr.address.is_tor() =>
+                Box::new(TorTransport { proxy_addr }.connect(&self.inet_addr).await?),
+            AddrScheme::Tcp =>
+                Box::new(TcpTransport.connect(&self.inet_addr).await?),
+            #[cfg(feature="use-websocket")]
+            AddrScheme::Ws =>
+                Box::new(WsTransport { secure: false }.connect(&self.inet_addr).await?),
+            #[cfg(feature="use-websocket")]
+            AddrScheme::Wss =>
+                Box::new(WsTransport { secure: true }.connect(&self.inet_addr).await?),
+            #[cfg(not(feature="use-websocket"))]
+            AddrScheme::Ws | AddrScheme::Wss =>
+                Err(ConnectionError::WebSocketNotYetSupported)?
+        };
+
+        #[cfg(feature="use-obfs")]
+        let stream: Box<dyn AsyncDuplex> = if self.obfuscated {
+            let secret = obfs_secret.ok_or_else(|| ConnectionError::FailedHandshake(
+                "obfuscated NodeAddr requires a pre-shared obfuscation secret".to_string()
+            ))?;
+            Box::new(ObfsStream::new(stream, &secret, true, Default::default()))
+        } else {
+            stream
+        };
+
+        Connection::new(stream, &self.node_id, private_key, ephemeral_private_key).await
+    }
+}
+
+impl fmt::Display for NodeAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@", self.node_id)?;
+        if self.obfuscated {
+            write!(f, "obfs+")?;
+        }
+        match self.scheme {
+            AddrScheme::Tcp => write!(f, "{}", self.inet_addr),
+            AddrScheme::Ws => write!(f, "ws://{}", self.inet_addr),
+            AddrScheme::Wss => write!(f, "wss://{}", self.inet_addr),
+        }
+    }
+}
+
+impl FromStr for NodeAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err_msg = "Wrong LN peer id; it must be in format \
+                            `<node_id>@[obfs+][ws://|wss://]<node_inet_addr>[:<port>]`, \
+                            where <node_inet_addr> may be IPv4, IPv6 or TORv3 address\
+                            ";
+
+        let mut splitter = s.split('@');
+        let (id, inet) = match (splitter.next(), splitter.next(), splitter.next()) {
+            (Some(id), Some(inet), None) => (id, inet),
+            _ => Err(String::from(err_msg))?
+        };
+
+        let (obfuscated, inet) = match inet.strip_prefix("obfs+") {
+            Some(rest) => (true, rest),
+            None => (false, inet)
+        };
+
+        let (scheme, inet) = if let Some(rest) = inet.strip_prefix("wss://") {
+            (AddrScheme::Wss, rest)
+        } else if let Some(rest) = inet.strip_prefix("ws://") {
+            (AddrScheme::Ws, rest)
+        } else {
+            (AddrScheme::Tcp, inet)
+        };
+
+        let mut splitter = inet.split(':');
+        let (addr, port) = match (splitter.next(), splitter.next(), splitter.next()) {
+            (Some(addr), Some(port), None) =>
+                (addr, port.parse().map_err(|_| err_msg)?),
+            (Some(addr), None, _) => (addr, LIGHTNING_P2P_DEFAULT_PORT),
+            _ => Err(String::from(err_msg))?
+        };
+
+        Ok(Self {
+            node_id: id.parse().map_err(|_| err_msg)?,
+            inet_addr: InetSocketAddr::new(addr.parse().map_err(|_| err_msg)?, port),
+            scheme,
+            obfuscated
+        })
+    }
+}
+
+
+#[derive(Debug, Display)]
+#[display_from(Debug)]
+pub enum ConnectionError {
+    TorNotYetSupported,
+    WebSocketNotYetSupported,
+    Socks5Error(String),
+    FailedHandshake(String),
+    IoError(io::Error)
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(err: io::Error) -> Self {
+        ConnectionError::IoError(err)
+    }
+}
+
+
+/// Dials a byte stream to a [`InetSocketAddr`], abstracting over the
+/// concrete network path (plain TCP, a Tor SOCKS5 proxy, a WebSocket
+/// upgrade, ...) so that [`Connection`] only ever has to deal with an
+/// `AsyncRead + AsyncWrite` stream and can stay oblivious of how it was
+/// obtained
+#[async_trait::async_trait]
+pub trait Transport {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    async fn connect(&self, addr: &InetSocketAddr) -> Result<Self::Stream, ConnectionError>;
+}
+
+/// Object-safe shorthand for a [`Transport::Stream`], letting
+/// [`NodeAddr::connect`] return a single concrete `Connection` type
+/// regardless of which transport (and, optionally, obfuscation layer)
+/// was selected at runtime
+pub trait AsyncDuplex: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncDuplex for T {}
+
+
+pub struct Connection<S> {
+    pub stream: S,
+    pub outbound: bool,
+    encryptor: Encryptor,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    /// Drives the initiator side of the BOLT-8 handshake
+    /// (`PeerHandshake::new_outbound`) over an already-connected `stream`,
+    /// whatever [`Transport`] produced it
+    pub async fn new(
+        stream: S,
+        node_id: &secp256k1::PublicKey,
+        private_key: &secp256k1::SecretKey,
+        ephemeral_private_key: &secp256k1::SecretKey
+    ) -> Result<Self, ConnectionError> {
+        #[cfg(feature="use-log")]
+        trace!("Starting handshake procedure with {}", node_id);
+
+        let handshake = PeerHandshake::new_outbound(
+            private_key, node_id, ephemeral_private_key
+        );
+        let (stream, encryptor) = Self::drive_handshake(stream, handshake, false).await?;
+
+        Ok(Self {
+            stream,
+            outbound: true,
+            encryptor
+        })
+    }
+
+    /// Accepts an already-established inbound stream and drives the
+    /// responder side of the BOLT-8 handshake (`PeerHandshake::new_inbound`)
+    /// to completion. Use [`ConnectionListener`] to also perform the
+    /// `TcpListener::accept` step.
+    pub async fn accept(
+        stream: S,
+        private_key: &secp256k1::SecretKey,
+        ephemeral_private_key: &secp256k1::SecretKey
+    ) -> Result<Self, ConnectionError> {
+        #[cfg(feature="use-log")]
+        trace!("Starting inbound handshake procedure");
+
+        let handshake = PeerHandshake::new_inbound(private_key, ephemeral_private_key);
+        let (stream, encryptor) = Self::drive_handshake(stream, handshake, true).await?;
+
+        Ok(Self {
+            stream,
+            outbound: false,
+            encryptor
+        })
+    }
+
+    /// Runs the BOLT-8 act-processing loop shared by the outbound
+    /// (`new`) and inbound (`accept`) connection paths. `responder` must
+    /// be `true` for `accept`'s `PeerHandshake::new_inbound`: unlike the
+    /// initiator, which generates Act One out of thin air, the responder
+    /// has nothing to process until Act One's bytes have actually arrived
+    /// off the wire, so that first read has to happen before the loop's
+    /// first `process_act` call rather than after it.
+    async fn drive_handshake(
+        mut stream: S,
+        mut handshake: PeerHandshake,
+        responder: bool
+    ) -> Result<(S, Encryptor), ConnectionError> {
+        let mut step = 0;
+        let mut input: &[u8] = &[];
+        let mut buf = vec![];
+        buf.reserve(MAX_TRANSPORT_FRAME_SIZE);
+
+        if responder {
+            #[cfg(feature="use-log")]
+            trace!("Waiting for the initiator's Act One");
+
+            buf.clear();
+            let read_len = stream.read_buf(&mut buf).await?;
+            input = &buf[0..read_len];
+        }
+
+        let result: Result<Encryptor, ConnectionError> = loop {
+            #[cfg(feature="use-log")]
+            trace!("Handshake step {}: processing data `{:x?}`", step, input);
+
+            let (act, enc) = handshake.process_act(input)
+                .map_err(|msg| ConnectionError::FailedHandshake(msg))?;
+
+            if act.is_none() && enc.is_none() {
+                #[cfg(feature="use-log")]
+                error!("`PeerHandshake.process_act` returned non-standard result");
+
+                Err(ConnectionError::FailedHandshake(
+                    "PeerHandshake.process_act returned non-standard result"
+                        .to_string()
+                ))?
+            }
+
+            // The initiator gets Act Three together with the encryptor, so
+            // an act has to go out before the loop ends on the encryptor
+            if let Some(act) = act {
+                #[cfg(feature="use-log")]
+                trace!("Handshake step {}: sending `{:x?}`", step, act.serialize());
+
+                stream.write_all(&act.serialize()).await?;
+            }
+
+            if let Some(encryptor) = enc {
+                break Ok(encryptor)
+            }
+
+            #[cfg(feature="use-log")]
+            trace!("Handshake step {}: waiting for response`", step);
+
+            buf.clear();
+            let read_len = stream.read_buf(&mut buf).await?;
+            input = &buf[0..read_len];
+
+            #[cfg(feature="use-log")]
+            trace!("Handshake step {}: received data `{:x?}`", step, input);
+
+            step += 1;
+        };
+        let encryptor = result?;
+
+        #[cfg(feature="use-log")]
+        trace!("Handshake successfully completed");
+
+        Ok((stream, encryptor))
+    }
+}
+
+fn random_ephemeral_key() -> secp256k1::SecretKey {
+    use lightning::secp256k1::rand::RngCore;
+
+    let mut rng = lightning::secp256k1::rand::thread_rng();
+    loop {
+        let mut bytes = [0u8; 32];
+        rng.fill_bytes(&mut bytes);
+        if let Ok(key) = secp256k1::SecretKey::from_slice(&bytes) {
+            break key;
+        }
+    }
+}
+
+
+/// Listens on a TCP socket and completes the responder side of the
+/// BOLT-8 handshake for each incoming peer, mirroring the
+/// listening-port / announced-address flow node implementations expose
+/// for accepting inbound connections
+pub struct ConnectionListener {
+    listener: TcpListener,
+    private_key: secp256k1::SecretKey,
+    /// When set, every accepted stream is wrapped in [`ObfsStream`]
+    /// (responder side) before the BOLT-8 handshake starts, mirroring
+    /// [`NodeAddr::connect`]'s dialer-side `obfuscated` handling so an
+    /// obfuscated peer can also be listened for, not just dialed out to
+    #[cfg(feature="use-obfs")]
+    obfs_secret: Option<[u8; 32]>,
+}
+
+impl ConnectionListener {
+    pub async fn bind(
+        inet_addr: &InetSocketAddr,
+        private_key: secp256k1::SecretKey,
+        #[cfg(feature="use-obfs")]
+        obfs_secret: Option<[u8; 32]>
+    ) -> Result<Self, ConnectionError> {
+        let socket_addr: SocketAddr = (*inet_addr).into();
+
+        #[cfg(feature="use-log")]
+        trace!("Binding listening socket to {}", socket_addr);
+        let listener = TcpListener::bind(socket_addr).await?;
+
+        Ok(Self {
+            listener,
+            private_key,
+            #[cfg(feature="use-obfs")]
+            obfs_secret
+        })
+    }
+
+    pub async fn accept(&self) -> Result<Connection<Box<dyn AsyncDuplex>>, ConnectionError> {
+        let (stream, peer_addr) = self.listener.accept().await?;
+
+        #[cfg(feature="use-log")]
+        trace!("Accepted inbound TCP connection from {}", peer_addr);
+
+        let stream: Box<dyn AsyncDuplex> = Box::new(stream);
+
+        #[cfg(feature="use-obfs")]
+        let stream: Box<dyn AsyncDuplex> = match self.obfs_secret {
+            Some(secret) => Box::new(ObfsStream::new(stream, &secret, false, Default::default())),
+            None => stream,
+        };
+
+        let ephemeral_private_key = random_ephemeral_key();
+        Connection::accept(stream, &self.private_key, &ephemeral_private_key).await
+    }
+}
+
+#[cfg(all(test, feature="use-tokio"))]
+mod tests {
+    use super::*;
+
+    /// Drives Connection::new and Connection::accept against each other
+    /// over an in-memory duplex pair, exercising the full three-act
+    /// BOLT-8 handshake both sides of drive_handshake take. This is the
+    /// multi-read responder path a single-shot `buf[0..read_len]` slice
+    /// (without clearing `buf` between reads) silently corrupts, despite
+    /// compiling and despite the pre-loop read alone looking correct.
+    #[tokio::test]
+    async fn accept_completes_a_real_handshake_against_new() {
+        let (client, server) = tokio::io::duplex(4096);
+
+        let initiator_private_key = random_ephemeral_key();
+        let initiator_ephemeral_key = random_ephemeral_key();
+        let responder_private_key = random_ephemeral_key();
+        let responder_ephemeral_key = random_ephemeral_key();
+
+        let secp = secp256k1::Secp256k1::new();
+        let responder_node_id = secp256k1::PublicKey::from_secret_key(&secp, &responder_private_key);
+
+        let (initiator_result, responder_result) = tokio::join!(
+            Connection::new(client, &responder_node_id, &initiator_private_key, &initiator_ephemeral_key),
+            Connection::accept(server, &responder_private_key, &responder_ephemeral_key)
+        );
+
+        initiator_result.expect("initiator side of the handshake should complete");
+        responder_result.expect("responder side of the handshake should complete");
+    }
+}
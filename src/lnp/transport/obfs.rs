@@ -0,0 +1,466 @@
+// LNP/BP Rust Library
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Pluggable obfs4-style obfuscation layer (feature `use-obfs`) sitting
+//! between the raw socket and [`PeerHandshake`], so that the on-wire
+//! bytes of a BOLT-8 connection don't carry the fixed-structure,
+//! 50-byte-first-message fingerprint of an un-obfuscated Act 1.
+//!
+//! ## Wire format
+//!
+//! Both ends derive two 32-byte keys from a pre-shared secret
+//! (`obfs-initiator-to-responder` and `obfs-responder-to-initiator`,
+//! see [`derive_direction_key`]), one per direction. From each of these
+//! two further keys are derived the same way (`obfs-stream` and
+//! `obfs-pad-length`), each seeding an independent [`Keystream`]:
+//! a SHA256-based counter-mode generator producing
+//! `SHA256(key || counter)` blocks that are consumed byte-by-byte.
+//!
+//! Every transport-level write becomes one frame:
+//!
+//! ```text
+//! [ 8-byte header, XORed with the stream keystream ]
+//!     [ 4-byte BE payload length ][ 4-byte BE padding length ]
+//! [ payload, XORed with the stream keystream ]
+//! [ padding, XORed with the stream keystream ]
+//! ```
+//!
+//! The padding length for a given frame is drawn from the *separate*
+//! `obfs-pad-length` keystream (so that adding or removing a byte of
+//! real payload doesn't change the bytes the length-keystream would
+//! have produced for a later frame) and clamped into
+//! [`PaddingRange`]. Because the header is itself XORed with a keystream
+//! that only the two ends share, neither the true payload length nor the
+//! frame boundary is visible on the wire, and because the keystream
+//! position always advances by exactly `8 + payload_len + pad_len`
+//! bytes per frame, a decoder that processes frames in order (guaranteed
+//! by the underlying reliable, ordered transport) never needs an
+//! explicit resynchronization step — the two sides are back in lock step
+//! as soon as a full frame has been read, even after a partial
+//! `poll_read` returned fewer bytes than the frame boundary.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[cfg(feature="use-tokio")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(not(feature="use-tokio"))]
+use std::io::{AsyncRead, AsyncWrite};
+
+use bitcoin::hashes::{sha256, Hash};
+
+use crate::common::internet::InetSocketAddr;
+use super::{ConnectionError, Transport, MAX_TRANSPORT_FRAME_SIZE};
+
+
+const HEADER_LEN: usize = 8;
+
+fn derive_direction_key(secret: &[u8; 32], label: &[u8]) -> [u8; 32] {
+    let mut preimage = Vec::with_capacity(secret.len() + label.len());
+    preimage.extend_from_slice(secret);
+    preimage.extend_from_slice(label);
+    sha256::Hash::hash(&preimage).into_inner()
+}
+
+/// SHA256-counter-mode keystream: `block_i = SHA256(key || i_be_u64)`,
+/// consumed one byte at a time
+struct Keystream {
+    key: [u8; 32],
+    counter: u64,
+    block: [u8; 32],
+    pos: usize,
+}
+
+impl Keystream {
+    fn new(key: [u8; 32]) -> Self {
+        Self { key, counter: 0, block: [0u8; 32], pos: 32 }
+    }
+
+    fn next_block(&mut self) {
+        let mut preimage = Vec::with_capacity(40);
+        preimage.extend_from_slice(&self.key);
+        preimage.extend_from_slice(&self.counter.to_be_bytes());
+        self.block = sha256::Hash::hash(&preimage).into_inner();
+        self.counter += 1;
+        self.pos = 0;
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        if self.pos == self.block.len() {
+            self.next_block();
+        }
+        let byte = self.block[self.pos];
+        self.pos += 1;
+        byte
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte ^= self.next_byte();
+        }
+    }
+}
+
+/// Randomized padding length distribution applied to every frame
+#[derive(Clone, Copy, Debug)]
+pub struct PaddingRange {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Default for PaddingRange {
+    fn default() -> Self {
+        Self { min: 0, max: 255 }
+    }
+}
+
+impl PaddingRange {
+    fn sample(&self, keystream: &mut Keystream) -> usize {
+        let span = (self.max.saturating_sub(self.min) as u64) + 1;
+        let raw = u32::from_be_bytes([
+            0,
+            keystream.next_byte(),
+            keystream.next_byte(),
+            keystream.next_byte(),
+        ]) as u64;
+        self.min + (raw % span) as usize
+    }
+}
+
+
+enum ReadState {
+    Header { buf: [u8; HEADER_LEN], filled: usize },
+    Body { payload_len: usize, buf: Vec<u8>, filled: usize },
+    Ready { data: Vec<u8>, pos: usize },
+}
+
+struct PendingWrite {
+    frame: Vec<u8>,
+    written: usize,
+    reported_len: usize,
+}
+
+/// Wraps a stream `S` so that every byte written to, and read from, the
+/// wrapped [`Connection`](super::Connection) passes through the obfs4-style
+/// framing and keystream described at the module level
+pub struct ObfsStream<S> {
+    inner: S,
+    send_stream_key: Keystream,
+    send_pad_key: Keystream,
+    recv_stream_key: Keystream,
+    // Not read back: the padding length travels in the header itself.
+    // Derived anyway so both ends agree on the full set of sub-keys.
+    #[allow(dead_code)]
+    recv_pad_key: Keystream,
+    padding: PaddingRange,
+    write_pending: Option<PendingWrite>,
+    read_state: ReadState,
+}
+
+impl<S> ObfsStream<S> {
+    /// `outbound` picks which of the two directional keys derived from
+    /// `secret` this side uses for sending vs. receiving, so that the
+    /// dialer and the listener end up with mirrored send/recv keystreams
+    pub fn new(inner: S, secret: &[u8; 32], outbound: bool, padding: PaddingRange) -> Self {
+        let initiator_key = derive_direction_key(secret, b"obfs-initiator-to-responder");
+        let responder_key = derive_direction_key(secret, b"obfs-responder-to-initiator");
+        let (send_key, recv_key) = if outbound {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+
+        Self {
+            inner,
+            send_stream_key: Keystream::new(derive_direction_key(&send_key, b"obfs-stream")),
+            send_pad_key: Keystream::new(derive_direction_key(&send_key, b"obfs-pad-length")),
+            recv_stream_key: Keystream::new(derive_direction_key(&recv_key, b"obfs-stream")),
+            recv_pad_key: Keystream::new(derive_direction_key(&recv_key, b"obfs-pad-length")),
+            padding,
+            write_pending: None,
+            read_state: ReadState::Header { buf: [0u8; HEADER_LEN], filled: 0 },
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ObfsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8]
+    ) -> Poll<io::Result<usize>> {
+        if buf.len() > MAX_TRANSPORT_FRAME_SIZE {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "obfuscation layer: frame exceeds MAX_TRANSPORT_FRAME_SIZE"
+            )));
+        }
+
+        let this = self.get_mut();
+
+        if this.write_pending.is_none() {
+            let pad_len = this.padding.sample(&mut this.send_pad_key);
+
+            let mut header = [0u8; HEADER_LEN];
+            header[0..4].copy_from_slice(&(buf.len() as u32).to_be_bytes());
+            header[4..8].copy_from_slice(&(pad_len as u32).to_be_bytes());
+            this.send_stream_key.apply(&mut header);
+
+            let mut payload = buf.to_vec();
+            this.send_stream_key.apply(&mut payload);
+
+            let mut padding = vec![0u8; pad_len];
+            this.send_stream_key.apply(&mut padding);
+
+            let mut frame = Vec::with_capacity(HEADER_LEN + payload.len() + padding.len());
+            frame.extend_from_slice(&header);
+            frame.extend_from_slice(&payload);
+            frame.extend_from_slice(&padding);
+
+            this.write_pending = Some(PendingWrite { frame, written: 0, reported_len: buf.len() });
+        }
+
+        loop {
+            let pending = this.write_pending.as_mut().expect("set above");
+            if pending.written == pending.frame.len() {
+                let reported_len = pending.reported_len;
+                this.write_pending = None;
+                return Poll::Ready(Ok(reported_len));
+            }
+
+            match Pin::new(&mut this.inner).poll_write(cx, &pending.frame[pending.written..]) {
+                Poll::Ready(Ok(0)) => {
+                    this.write_pending = None;
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "obfuscation layer: inner stream accepted 0 bytes"
+                    )))
+                },
+                Poll::Ready(Ok(n)) => pending.written += n,
+                Poll::Ready(Err(err)) => {
+                    this.write_pending = None;
+                    return Poll::Ready(Err(err));
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    #[cfg(feature="use-tokio")]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    #[cfg(not(feature="use-tokio"))]
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ObfsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.read_state {
+                ReadState::Ready { data, pos } => {
+                    if *pos < data.len() {
+                        let n = std::cmp::min(buf.remaining(), data.len() - *pos);
+                        buf.put_slice(&data[*pos..*pos + n]);
+                        *pos += n;
+                        return Poll::Ready(Ok(()));
+                    }
+                    this.read_state = ReadState::Header { buf: [0u8; HEADER_LEN], filled: 0 };
+                },
+                ReadState::Header { buf: header_buf, filled } => {
+                    let mut inner_buf = ReadBuf::new(&mut header_buf[*filled..]);
+                    match Pin::new(&mut this.inner).poll_read(cx, &mut inner_buf) {
+                        Poll::Ready(Ok(())) => {
+                            let n = inner_buf.filled().len();
+                            if n == 0 {
+                                return Poll::Ready(Ok(()));
+                            }
+                            *filled += n;
+                            if *filled == HEADER_LEN {
+                                let mut header = *header_buf;
+                                this.recv_stream_key.apply(&mut header);
+                                let payload_len = u32::from_be_bytes(
+                                    [header[0], header[1], header[2], header[3]]
+                                ) as usize;
+                                let pad_len = u32::from_be_bytes(
+                                    [header[4], header[5], header[6], header[7]]
+                                ) as usize;
+
+                                if payload_len > MAX_TRANSPORT_FRAME_SIZE {
+                                    return Poll::Ready(Err(io::Error::new(
+                                        io::ErrorKind::InvalidData,
+                                        "obfuscation layer: frame exceeds MAX_TRANSPORT_FRAME_SIZE"
+                                    )));
+                                }
+                                // pad_len comes off the wire just like
+                                // payload_len and is just as attacker
+                                // controlled, so it needs the same bound
+                                // before it feeds a `vec![0u8; ...]`
+                                // allocation below
+                                if pad_len > this.padding.max {
+                                    return Poll::Ready(Err(io::Error::new(
+                                        io::ErrorKind::InvalidData,
+                                        "obfuscation layer: padding length exceeds configured PaddingRange"
+                                    )));
+                                }
+
+                                this.read_state = ReadState::Body {
+                                    payload_len,
+                                    buf: vec![0u8; payload_len + pad_len],
+                                    filled: 0,
+                                };
+                            }
+                        },
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                },
+                ReadState::Body { payload_len, buf: body_buf, filled } => {
+                    let mut inner_buf = ReadBuf::new(&mut body_buf[*filled..]);
+                    match Pin::new(&mut this.inner).poll_read(cx, &mut inner_buf) {
+                        Poll::Ready(Ok(())) => {
+                            let n = inner_buf.filled().len();
+                            if n == 0 {
+                                return Poll::Ready(Ok(()));
+                            }
+                            *filled += n;
+                            if *filled == body_buf.len() {
+                                this.recv_stream_key.apply(body_buf);
+                                let data = body_buf[..*payload_len].to_vec();
+                                this.read_state = ReadState::Ready { data, pos: 0 };
+                            }
+                        },
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Wraps another [`Transport`] so every stream it produces is run
+/// through [`ObfsStream`] before [`Connection`](super::Connection) sees it
+pub struct ObfsTransport<T> {
+    pub inner: T,
+    pub secret: [u8; 32],
+    pub padding: PaddingRange,
+}
+
+#[async_trait::async_trait]
+impl<T: Transport + Sync> Transport for ObfsTransport<T> {
+    type Stream = ObfsStream<T::Stream>;
+
+    async fn connect(&self, addr: &InetSocketAddr) -> Result<Self::Stream, ConnectionError> {
+        let inner = self.inner.connect(addr).await?;
+        Ok(ObfsStream::new(inner, &self.secret, true, self.padding))
+    }
+}
+
+#[cfg(all(test, feature="use-tokio"))]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn direction_keys_differ_and_are_deterministic() {
+        let secret = [7u8; 32];
+        let initiator = derive_direction_key(&secret, b"obfs-initiator-to-responder");
+        let responder = derive_direction_key(&secret, b"obfs-responder-to-initiator");
+        assert_ne!(initiator, responder);
+        assert_eq!(initiator, derive_direction_key(&secret, b"obfs-initiator-to-responder"));
+    }
+
+    #[test]
+    fn keystreams_seeded_from_the_same_key_agree() {
+        let key = [9u8; 32];
+        let mut a = Keystream::new(key);
+        let mut b = Keystream::new(key);
+        let mut data = [0x42u8; 100];
+        a.apply(&mut data);
+        // Applying the same keystream from a fresh, identically-seeded
+        // instance a second time is the decryption side of the XOR, and
+        // should recover the original bytes
+        b.apply(&mut data);
+        assert_eq!(data, [0x42u8; 100]);
+    }
+
+    #[test]
+    fn padding_sample_stays_within_range() {
+        let range = PaddingRange { min: 10, max: 20 };
+        let mut keystream = Keystream::new([3u8; 32]);
+        for _ in 0..1000 {
+            let sample = range.sample(&mut keystream);
+            assert!(sample >= range.min && sample <= range.max);
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_roundtrips_both_directions() {
+        let secret = [11u8; 32];
+        let (client, server) = tokio::io::duplex(4096);
+        let mut dialer = ObfsStream::new(client, &secret, true, Default::default());
+        let mut listener = ObfsStream::new(server, &secret, false, Default::default());
+
+        dialer.write_all(b"act one").await.unwrap();
+        let mut buf = [0u8; 7];
+        listener.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"act one");
+
+        listener.write_all(b"act two").await.unwrap();
+        let mut buf = [0u8; 7];
+        dialer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"act two");
+    }
+
+    #[tokio::test]
+    async fn oversized_pad_len_is_rejected_instead_of_allocated() {
+        let secret = [13u8; 32];
+        let (client, mut server_raw) = tokio::io::duplex(4096);
+        let mut listener = ObfsStream::new(client, &secret, false, Default::default());
+
+        // Craft a frame with a huge pad_len straight onto the wire,
+        // bypassing ObfsStream::poll_write's own (honest) PaddingRange.
+        // The listener (outbound: false) receives on the
+        // initiator-to-responder key, so that's what has to encrypt
+        // this forged header for it to decode at all
+        let mut send_key = Keystream::new(
+            derive_direction_key(
+                &derive_direction_key(&secret, b"obfs-initiator-to-responder"),
+                b"obfs-stream"
+            )
+        );
+        let mut header = [0u8; HEADER_LEN];
+        header[0..4].copy_from_slice(&0u32.to_be_bytes());
+        header[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+        send_key.apply(&mut header);
+        server_raw.write_all(&header).await.unwrap();
+
+        let mut buf = [0u8; 1];
+        let result = listener.read(&mut buf).await;
+        assert!(result.is_err());
+    }
+}
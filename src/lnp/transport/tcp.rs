@@ -0,0 +1,47 @@
+// LNP/BP Rust Library
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Plain TCP [`Transport`] used for clearnet (non-Tor) peers
+
+use std::net::SocketAddr;
+use std::convert::TryInto;
+
+#[cfg(feature="use-tokio")]
+use tokio::net::TcpStream;
+
+#[cfg(not(feature="use-tokio"))]
+use std::net::TcpStream;
+
+use crate::common::internet::InetSocketAddr;
+use super::{ConnectionError, Transport};
+
+
+/// Dials the target address directly over clearnet TCP
+pub struct TcpTransport;
+
+#[async_trait::async_trait]
+impl Transport for TcpTransport {
+    type Stream = TcpStream;
+
+    async fn connect(&self, addr: &InetSocketAddr) -> Result<TcpStream, ConnectionError> {
+        #[cfg(feature="use-tor")]
+        let socket_addr: SocketAddr = (*addr).try_into().unwrap();
+        #[cfg(not(feature="use-tor"))]
+        let socket_addr: SocketAddr = (*addr).into();
+
+        #[cfg(feature="use-log")]
+        trace!("Connecting to {}", socket_addr);
+
+        Ok(TcpStream::connect(socket_addr).await?)
+    }
+}
@@ -0,0 +1,348 @@
+// LNP/BP Rust Library
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Tor [`Transport`] dialing a target `.onion` address through a local
+//! SOCKS5 proxy, plus [`TorHiddenService`] for publishing our own onion
+//! address through the Tor control port
+
+use std::net::SocketAddr;
+
+#[cfg(feature="use-tokio")]
+use tokio::net::TcpStream;
+#[cfg(feature="use-tokio")]
+use tokio::io::{AsyncWriteExt, AsyncReadExt, AsyncBufReadExt, BufReader};
+
+#[cfg(not(feature="use-tokio"))]
+use std::net::TcpStream;
+#[cfg(not(feature="use-tokio"))]
+use std::io::{AsyncWriteExt, AsyncReadExt, AsyncBufReadExt, BufReader};
+
+use crate::common::internet::InetSocketAddr;
+use super::{ConnectionError, Transport};
+
+
+/// Default SOCKS5 port used by a locally running Tor daemon
+pub const TOR_PROXY_DEFAULT_PORT: u16 = 9050;
+
+fn default_tor_proxy() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], TOR_PROXY_DEFAULT_PORT))
+}
+
+
+/// Dials a `.onion` target through a Tor SOCKS5 proxy, defaulting to a
+/// locally running `tor` daemon at `127.0.0.1:9050`. Without the
+/// `use-tor` feature `connect` always fails with
+/// [`ConnectionError::TorNotYetSupported`].
+pub struct TorTransport {
+    pub proxy_addr: Option<SocketAddr>,
+}
+
+#[async_trait::async_trait]
+impl Transport for TorTransport {
+    type Stream = TcpStream;
+
+    #[cfg(not(feature="use-tor"))]
+    async fn connect(&self, _addr: &InetSocketAddr) -> Result<TcpStream, ConnectionError> {
+        Err(ConnectionError::TorNotYetSupported)
+    }
+
+    #[cfg(feature="use-tor")]
+    async fn connect(&self, addr: &InetSocketAddr) -> Result<TcpStream, ConnectionError> {
+        let proxy = self.proxy_addr.unwrap_or_else(default_tor_proxy);
+
+        #[cfg(feature="use-log")]
+        trace!("Connecting to {} through the Tor proxy at {}", addr, proxy);
+
+        let mut stream = TcpStream::connect(proxy).await?;
+
+        // Greeting: SOCKS version 5, one authentication method, no auth
+        stream.write_all(&[0x05, 0x01, 0x00]).await?;
+        let mut method_selection = [0u8; 2];
+        stream.read_exact(&mut method_selection).await?;
+        socks5_check_method_selection(method_selection)?;
+
+        // CONNECT request using the domain name address type, so that the
+        // onion hostname is resolved by the proxy rather than locally
+        let host = addr.address.to_string();
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+        request.extend_from_slice(host.as_bytes());
+        request.extend_from_slice(&addr.port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply_header = [0u8; 4];
+        stream.read_exact(&mut reply_header).await?;
+        socks5_check_reply_code(reply_header[1])?;
+
+        // The reply carries a bound address we don't need, but we still
+        // have to read it off the stream before it's usable for the
+        // handshake that follows
+        match reply_header[3] {
+            0x03 => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len).await?;
+                let mut rest = vec![0u8; len[0] as usize + 2];
+                stream.read_exact(&mut rest).await?;
+            },
+            addr_type => {
+                let mut rest = vec![0u8; socks5_bound_addr_len(addr_type)?];
+                stream.read_exact(&mut rest).await?;
+            }
+        }
+
+        Ok(stream)
+    }
+}
+
+/// Fails unless `reply` is the SOCKS5 server picking "no authentication",
+/// the only method our greeting offers
+#[cfg(feature="use-tor")]
+fn socks5_check_method_selection(reply: [u8; 2]) -> Result<(), ConnectionError> {
+    if reply != [0x05, 0x00] {
+        Err(ConnectionError::Socks5Error(
+            "Tor proxy rejected the no-authentication method".to_string()
+        ))?
+    }
+    Ok(())
+}
+
+/// Fails unless `code` (the second byte of a SOCKS5 CONNECT reply) is
+/// `0x00` ("succeeded")
+#[cfg(feature="use-tor")]
+fn socks5_check_reply_code(code: u8) -> Result<(), ConnectionError> {
+    if code != 0x00 {
+        Err(ConnectionError::Socks5Error(format!(
+            "Tor proxy refused the connection, reply code {:#04x}", code
+        )))?
+    }
+    Ok(())
+}
+
+/// Number of bytes still to read for a SOCKS5 CONNECT reply's bound
+/// address (including its trailing 2-byte port), for the two
+/// fixed-length address types. `0x03` (domain name) carries its own
+/// length byte and is handled separately by the caller.
+#[cfg(feature="use-tor")]
+fn socks5_bound_addr_len(addr_type: u8) -> Result<usize, ConnectionError> {
+    match addr_type {
+        0x01 => Ok(4 + 2),
+        0x04 => Ok(16 + 2),
+        _ => Err(ConnectionError::Socks5Error(
+            "Tor proxy returned an unknown bound address type".to_string()
+        ))
+    }
+}
+
+#[cfg(all(test, feature="use-tor"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn method_selection_accepts_no_auth() {
+        assert!(socks5_check_method_selection([0x05, 0x00]).is_ok());
+    }
+
+    #[test]
+    fn method_selection_rejects_anything_else() {
+        assert!(socks5_check_method_selection([0x05, 0x02]).is_err());
+        assert!(socks5_check_method_selection([0x04, 0x00]).is_err());
+    }
+
+    #[test]
+    fn reply_code_zero_is_success() {
+        assert!(socks5_check_reply_code(0x00).is_ok());
+    }
+
+    #[test]
+    fn reply_code_nonzero_is_failure() {
+        assert!(socks5_check_reply_code(0x01).is_err());
+        assert!(socks5_check_reply_code(0x05).is_err());
+    }
+
+    #[test]
+    fn bound_addr_len_for_ipv4_and_ipv6() {
+        assert_eq!(socks5_bound_addr_len(0x01).unwrap(), 4 + 2);
+        assert_eq!(socks5_bound_addr_len(0x04).unwrap(), 16 + 2);
+    }
+
+    #[test]
+    fn bound_addr_len_rejects_unknown_type() {
+        assert!(socks5_bound_addr_len(0x02).is_err());
+    }
+}
+
+
+/// Default control port used by a locally running Tor daemon
+#[cfg(feature="use-tor")]
+pub const TOR_CONTROL_DEFAULT_PORT: u16 = 9051;
+
+#[cfg(feature="use-tor")]
+fn default_tor_control() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], TOR_CONTROL_DEFAULT_PORT))
+}
+
+#[cfg(feature="use-tor")]
+async fn read_control_reply(
+    control: &mut BufReader<TcpStream>
+) -> Result<String, ConnectionError> {
+    let mut line = String::new();
+    control.read_line(&mut line).await?;
+    if line.is_empty() {
+        Err(ConnectionError::Socks5Error(
+            "Tor control port closed the connection".to_string()
+        ))?
+    }
+    Ok(line.trim_end().to_string())
+}
+
+/// Authenticates to an already-connected control port, picking
+/// `CookieAuthentication` (the common real-world setup, where
+/// `ControlPort` is enabled alongside `CookieAuthentication 1`) over the
+/// `NULL` method, and failing with a descriptive error for everything
+/// else (in particular `SAFECOOKIE` and `HASHEDPASSWORD`, neither of
+/// which is implemented here)
+#[cfg(feature="use-tor")]
+async fn authenticate(control: &mut BufReader<TcpStream>) -> Result<(), ConnectionError> {
+    control.get_mut().write_all(b"PROTOCOLINFO 1\r\n").await?;
+
+    let mut methods: Vec<String> = vec![];
+    let mut cookie_file: Option<String> = None;
+    loop {
+        let line = read_control_reply(control).await?;
+        if let Some(rest) = line.strip_prefix("250-AUTH ") {
+            for field in rest.split_whitespace() {
+                if let Some(list) = field.strip_prefix("METHODS=") {
+                    methods = list.split(',').map(str::to_string).collect();
+                } else if let Some(path) = field.strip_prefix("COOKIEFILE=") {
+                    cookie_file = Some(path.trim_matches('"').to_string());
+                }
+            }
+        } else if line.starts_with("250 OK") {
+            break;
+        }
+    }
+
+    if methods.iter().any(|method| method == "COOKIE") {
+        let path = cookie_file.ok_or_else(|| ConnectionError::Socks5Error(
+            "Tor control port advertised COOKIE authentication without a COOKIEFILE".to_string()
+        ))?;
+        let cookie = std::fs::read(&path).map_err(|err| ConnectionError::Socks5Error(
+            format!("failed to read Tor control cookie at {}: {}", path, err)
+        ))?;
+        let cookie_hex = cookie.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        control.get_mut().write_all(
+            format!("AUTHENTICATE {}\r\n", cookie_hex).as_bytes()
+        ).await?;
+    } else if methods.iter().any(|method| method == "NULL") {
+        control.get_mut().write_all(b"AUTHENTICATE\r\n").await?;
+    } else {
+        Err(ConnectionError::Socks5Error(format!(
+            "Tor control port only offers unsupported authentication methods {:?} \
+             (SAFECOOKIE and HASHEDPASSWORD aren't implemented; enable \
+             `CookieAuthentication 1` instead)",
+            methods
+        )))?
+    }
+
+    let reply = read_control_reply(control).await?;
+    if !reply.starts_with("250") {
+        Err(ConnectionError::Socks5Error(
+            format!("Tor control port refused authentication: {}", reply)
+        ))?
+    }
+
+    Ok(())
+}
+
+/// An ephemeral v3 onion service published through the Tor control port
+/// (`ADD_ONION`), mapping `virtual_port` to a locally bound
+/// [`super::ConnectionListener`] socket. The service is torn down
+/// (`DEL_ONION`) when this value is dropped.
+#[cfg(feature="use-tor")]
+pub struct TorHiddenService {
+    service_id: String,
+    control: Option<BufReader<TcpStream>>,
+}
+
+#[cfg(feature="use-tor")]
+impl TorHiddenService {
+    /// Authenticates to the Tor control port (default `127.0.0.1:9051`)
+    /// and requests an ephemeral onion service forwarding `virtual_port`
+    /// to `local_addr`. Pass `service_key` (the whole `KeyType:KeyBlob`
+    /// argument of Tor's `ADD_ONION`, e.g. `ED25519-V3:` followed by a
+    /// serialized key) to keep a stable address across restarts, or
+    /// `None` to have Tor generate a fresh key each time.
+    pub async fn publish(
+        virtual_port: u16,
+        local_addr: SocketAddr,
+        control_addr: Option<SocketAddr>,
+        service_key: Option<String>
+    ) -> Result<(Self, InetSocketAddr), ConnectionError> {
+        let control_addr = control_addr.unwrap_or_else(default_tor_control);
+
+        #[cfg(feature="use-log")]
+        trace!("Connecting to the Tor control port at {}", control_addr);
+        let stream = TcpStream::connect(control_addr).await?;
+        let mut control = BufReader::new(stream);
+
+        authenticate(&mut control).await?;
+
+        let key_arg = service_key.unwrap_or_else(|| "NEW:ED25519-V3".to_string());
+        let command = format!(
+            "ADD_ONION {} Flags=DiscardPK Port={},{}\r\n",
+            key_arg, virtual_port, local_addr
+        );
+        control.get_mut().write_all(command.as_bytes()).await?;
+
+        let reply = read_control_reply(&mut control).await?;
+        let service_id = reply.strip_prefix("250-ServiceID=")
+            .ok_or_else(|| ConnectionError::Socks5Error(
+                format!("Tor control port refused ADD_ONION: {}", reply)
+            ))?
+            .to_string();
+        // Consume the trailing "250 OK" line that closes the reply
+        read_control_reply(&mut control).await?;
+
+        #[cfg(feature="use-log")]
+        trace!("Published onion service {}.onion", service_id);
+
+        let onion_addr = InetSocketAddr::new(
+            format!("{}.onion", service_id).parse().map_err(|_| ConnectionError::Socks5Error(
+                "Tor returned an invalid onion service id".to_string()
+            ))?,
+            virtual_port
+        );
+
+        Ok((Self { service_id, control: Some(control) }, onion_addr))
+    }
+}
+
+#[cfg(feature="use-tor")]
+impl Drop for TorHiddenService {
+    /// Best-effort `DEL_ONION` on drop. This has to spawn onto the
+    /// ambient Tokio runtime to run the send past `drop`'s own `&mut
+    /// self`, and `tokio::spawn` panics outside of one, so we check for
+    /// a running runtime first: if there isn't one (or the task doesn't
+    /// get to run before the process exits), the ephemeral service is
+    /// simply left for Tor to notice has gone away, rather than `drop`
+    /// taking the process down with it.
+    fn drop(&mut self) {
+        if let Some(mut control) = self.control.take() {
+            if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                let command = format!("DEL_ONION {}\r\n", self.service_id);
+                handle.spawn(async move {
+                    let _ = control.get_mut().write_all(command.as_bytes()).await;
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// LNP/BP Rust Library
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! WebSocket [`Transport`] (feature `use-websocket`), letting peers
+//! reachable only through a `ws://`/`wss://` endpoint (for example a node
+//! running in a browser) take part in the same BOLT-8 handshake as TCP
+//! peers. [`WsStream`] adapts a [`WebSocketStream`] to `AsyncRead` +
+//! `AsyncWrite` so the shared [`Connection::new`](super::Connection::new)
+//! act loop can drive it exactly like any other [`Transport::Stream`],
+//! each binary WS frame carrying the same bytes the TCP path would have
+//! sent, bounded by [`super::MAX_TRANSPORT_FRAME_SIZE`]
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::common::internet::InetSocketAddr;
+use super::{ConnectionError, Transport, MAX_TRANSPORT_FRAME_SIZE};
+
+fn ws_io_error(err: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+
+/// Dials a `ws://` or `wss://` peer, wrapping the resulting
+/// [`WebSocketStream`] in [`WsStream`] so it can be driven by the same
+/// [`Connection`](super::Connection) handshake loop TCP peers use
+pub struct WsTransport {
+    pub secure: bool,
+}
+
+#[async_trait::async_trait]
+impl Transport for WsTransport {
+    type Stream = WsStream;
+
+    async fn connect(&self, addr: &InetSocketAddr) -> Result<WsStream, ConnectionError> {
+        let url = format!("{}://{}", if self.secure { "wss" } else { "ws" }, addr);
+
+        #[cfg(feature="use-log")]
+        trace!("Opening WebSocket connection to {}", url);
+
+        let (stream, _response) = connect_async(url).await.map_err(ws_io_error)?;
+
+        Ok(WsStream { inner: stream, read_buf: vec![], read_pos: 0 })
+    }
+}
+
+/// Adapts a [`WebSocketStream`] to `AsyncRead` + `AsyncWrite` by carrying
+/// each write as a single binary WS message and serving reads out of the
+/// most recently received one
+pub struct WsStream {
+    inner: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = std::cmp::min(buf.remaining(), this.read_buf.len() - this.read_pos);
+                buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(Message::Binary(data)))) => {
+                    this.read_buf = data;
+                    this.read_pos = 0;
+                },
+                // Ping/Pong/Close/Text frames carry nothing the BOLT-8
+                // stream cares about; tungstenite answers pings on our
+                // behalf, so we just wait for the next frame
+                Poll::Ready(Some(Ok(_))) => continue,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(ws_io_error(err))),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8]
+    ) -> Poll<io::Result<usize>> {
+        if buf.len() > MAX_TRANSPORT_FRAME_SIZE {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "WebSocket transport: frame exceeds MAX_TRANSPORT_FRAME_SIZE"
+            )));
+        }
+
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_ready(cx) {
+            Poll::Ready(Ok(())) => {},
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(ws_io_error(err))),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        match Pin::new(&mut this.inner).start_send(Message::Binary(buf.to_vec())) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(err) => Poll::Ready(Err(ws_io_error(err))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx).map_err(ws_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx).map_err(ws_io_error)
+    }
+}